@@ -0,0 +1,220 @@
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+use std::io::{Error, ErrorKind};
+
+use crate::{emit_yaml_docs_to_string, load_yaml_from_str};
+
+// a hash key reserved purely for declaring `&anchor` blocks that other
+// parts of the document reference via `*alias`. it exists only to give
+// anchors somewhere to live, so once aliases have been expanded it is
+// stripped out of every hash it appears in and never reaches the caller.
+pub const SHARED_ANCHORS_KEY: &str = "x--substitution--anchors";
+
+// the yaml merge key: `<<: *anchor` (or `<<: [*a, *b]`) splices the
+// referenced hash(es) into the current one. an explicit key already
+// present in the current hash always wins over a merged one; between
+// multiple merge sources, the earliest one listed wins.
+const MERGE_KEY: &str = "<<";
+
+// parse `yaml_str`, expand every `&anchor`/`*alias` pair and `<<` merge
+// key, strip the reserved `SHARED_ANCHORS_KEY` block, and re-emit the
+// result as yaml text. this is run before the first substitution pass so
+// that a `${{ .. }}` placeholder living inside an anchored block gets
+// carried along to every place that anchor is aliased, instead of only
+// the one place it was originally written.
+pub fn expand_yaml_anchors_str(yaml_str: &str) -> Result<String, Error> {
+    // yaml-rust resolves ordinary anchor/alias pairs itself while
+    // parsing, which means a self-referential alias (one that points to
+    // an anchor still being defined) never becomes a usable value: by
+    // the time the parser would resolve it, the anchor it points to
+    // isn't in its anchor table yet, so it silently turns into
+    // `Yaml::BadValue` instead of an error. detect that case ourselves,
+    // from the raw event stream, before it gets swallowed.
+    detect_alias_cycles(yaml_str)?;
+    let docs = load_yaml_from_str(yaml_str)?;
+    let expanded: Vec<Yaml> = docs.iter().map(expand_yaml_anchors).collect::<Result<_, _>>()?;
+    if expanded == docs {
+        // nothing to expand or strip: keep the original text (and its
+        // comments/formatting) untouched instead of needlessly
+        // re-serializing it
+        return Ok(yaml_str.to_string());
+    }
+    emit_yaml_docs_to_string(&expanded)
+}
+
+// walk `yaml_str`'s raw parse events (not the resolved `Yaml` tree, which
+// has already lost this information by the time it's built) looking for
+// an alias that refers to an anchor id that is still "open" -- ie. a
+// mapping or sequence that has started but not yet finished, which means
+// the alias is inside the very node its anchor names.
+fn detect_alias_cycles(yaml_str: &str) -> Result<(), Error> {
+    struct CycleDetector {
+        open: Vec<usize>,
+        found: Option<(usize, usize)>,
+    }
+
+    impl MarkedEventReceiver for CycleDetector {
+        fn on_event(&mut self, ev: Event, mark: Marker) {
+            if self.found.is_some() {
+                return;
+            }
+            match ev {
+                Event::MappingStart(aid) | Event::SequenceStart(aid) => self.open.push(aid),
+                Event::MappingEnd | Event::SequenceEnd => {
+                    self.open.pop();
+                }
+                Event::Alias(id) if self.open.contains(&id) => {
+                    self.found = Some((mark.line(), mark.col()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut detector = CycleDetector { open: Vec::new(), found: None };
+    let mut parser = Parser::new(yaml_str.chars());
+    parser.load(&mut detector, true).map_err(|e| {
+        let err_msg = format!("Failed to parse yaml file:\n{}", e);
+        Error::new(ErrorKind::InvalidInput, err_msg)
+    })?;
+    if let Some((line, col)) = detector.found {
+        let err_msg = format!(
+            "found a yaml alias at line {} column {} that refers to an \
+                anchor that is still being defined (the anchor is \
+                self-referential)",
+            line, col
+        );
+        return Err(Error::new(ErrorKind::InvalidInput, err_msg));
+    }
+    Ok(())
+}
+
+// deep clone `doc`, dropping every `SHARED_ANCHORS_KEY` entry, flattening
+// `<<` merge keys into their containing hash, and erroring out on any
+// `Yaml::Alias` left in the tree. yaml-rust resolves ordinary
+// anchor/alias pairs itself while parsing (that's why this is a deep
+// clone rather than a from-scratch anchor resolver), so a `Yaml::Alias`
+// surviving to this point means yaml-rust could not resolve it -- in
+// practice `detect_alias_cycles` above already catches the self-
+// referential case earlier (with a usable line/column), so this arm is
+// the defensive fallback for anything that slips past it.
+pub fn expand_yaml_anchors(doc: &Yaml) -> Result<Yaml, Error> {
+    match doc {
+        Yaml::Hash(h) => {
+            let mut new_hash = Hash::new();
+            if let Some(merge_val) = h.get(&Yaml::String(MERGE_KEY.to_string())) {
+                merge_into(&mut new_hash, merge_val)?;
+            }
+            for (k, v) in h.iter() {
+                if let Yaml::String(s) = k {
+                    if s == SHARED_ANCHORS_KEY || s == MERGE_KEY {
+                        continue;
+                    }
+                }
+                new_hash.insert(expand_yaml_anchors(k)?, expand_yaml_anchors(v)?);
+            }
+            Ok(Yaml::Hash(new_hash))
+        }
+        Yaml::Array(a) => {
+            let mut new_arr = Vec::with_capacity(a.len());
+            for v in a {
+                new_arr.push(expand_yaml_anchors(v)?);
+            }
+            Ok(Yaml::Array(new_arr))
+        }
+        Yaml::Alias(_) => {
+            let err_kind = ErrorKind::InvalidInput;
+            let err_msg = "found a yaml alias that could not be resolved (the \
+                anchor it refers to is self-referential or was never defined)"
+                .to_string();
+            Err(Error::new(err_kind, err_msg))
+        }
+        other => Ok(other.clone()),
+    }
+}
+
+// insert every key from `source` into `target` that isn't already there.
+// `source` is either the hash to merge in directly, or (for `<<: [a, b]`)
+// an array of hashes to merge in in order -- since `target.contains_key`
+// skips anything already inserted, the earliest source in the array wins
+// over later ones. any key the containing hash sets itself is inserted
+// into `target` after this function returns, overwriting a merged value,
+// so it wins over all of them. `source` may itself contain its own `<<` key (a
+// merge chained through another anchor), so it's run through
+// `expand_yaml_anchors` first -- the same function this is a helper
+// for -- to flatten that before any of its fields are copied in.
+fn merge_into(target: &mut Hash, source: &Yaml) -> Result<(), Error> {
+    match source {
+        Yaml::Hash(_) => {
+            if let Yaml::Hash(h) = expand_yaml_anchors(source)? {
+                for (k, v) in h.into_iter() {
+                    if !target.contains_key(&k) {
+                        target.insert(k, v);
+                    }
+                }
+            }
+            Ok(())
+        }
+        Yaml::Array(a) => {
+            for v in a {
+                merge_into(target, v)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_referential_anchor_errors_instead_of_silently_producing_badvalue() {
+        let err = expand_yaml_anchors_str("a: &x\n  self: *x\n").unwrap_err();
+        assert!(err.to_string().contains("self-referential"));
+    }
+
+    #[test]
+    fn ordinary_anchors_still_expand() {
+        let out = expand_yaml_anchors_str("base: &b\n  a: 1\ntop: *b\n").unwrap();
+        let doc = load_yaml_from_str(&out).unwrap();
+        assert_eq!(doc[0]["top"]["a"].as_i64().unwrap(), 1);
+    }
+
+    #[test]
+    fn merge_key_flattens_into_containing_hash() {
+        let out = expand_yaml_anchors_str(
+            "base: &b\n  a: 1\n  b: 2\ntop:\n  <<: *b\n  b: 3\n",
+        )
+        .unwrap();
+        let doc = load_yaml_from_str(&out).unwrap();
+        assert_eq!(doc[0]["top"]["a"].as_i64().unwrap(), 1);
+        // explicit key in `top` wins over the merged one
+        assert_eq!(doc[0]["top"]["b"].as_i64().unwrap(), 3);
+    }
+
+    #[test]
+    fn merge_key_array_sources_earlier_wins() {
+        let out = expand_yaml_anchors_str(
+            "a: &a\n  x: 1\nb: &b\n  x: 2\ntop:\n  <<: [*a, *b]\n",
+        )
+        .unwrap();
+        let doc = load_yaml_from_str(&out).unwrap();
+        assert_eq!(doc[0]["top"]["x"].as_i64().unwrap(), 1);
+    }
+
+    #[test]
+    fn merge_key_chained_through_another_merge_key_flattens_fully() {
+        let out = expand_yaml_anchors_str(
+            "y: &y\n  z: 9\nx: &x\n  <<: *y\n  foo: 1\ntop:\n  <<: *x\n",
+        )
+        .unwrap();
+        let doc = load_yaml_from_str(&out).unwrap();
+        assert_eq!(doc[0]["top"]["z"].as_i64().unwrap(), 9);
+        assert_eq!(doc[0]["top"]["foo"].as_i64().unwrap(), 1);
+        assert!(doc[0]["top"]["<<"].is_badvalue());
+    }
+}
@@ -0,0 +1,175 @@
+// private-use-area sentinels swapped in for `${{`/`}}` while they sit
+// inside a yaml comment, so that the substitution passes never see them
+// as real references; swapped back once substitution is done.
+const ESCAPED_OPEN: &str = "\u{E000}SUBST_OPEN\u{E000}";
+const ESCAPED_CLOSE: &str = "\u{E000}SUBST_CLOSE\u{E000}";
+
+// walk `yaml_str` line by line, tracking single/double-quote state, and
+// mask any `${{ .. }}` token that appears after an unquoted `#` so the
+// substitution passes below leave it untouched. a `#` only starts a
+// comment when it's preceded by whitespace (or is the first character on
+// the line) and isn't inside a quoted scalar, matching how yaml itself
+// decides what counts as a comment. a line inside a `|`/`>` block scalar
+// is never scanned for comments at all, since every `#` there is literal
+// content, not yaml syntax.
+pub(crate) fn mask_comment_references(yaml_str: &str) -> String {
+    let mut out = Vec::new();
+    // indentation of the block scalar currently open, if any; a line
+    // that's blank or indented further than this is still inside it
+    let mut block_indent: Option<usize> = None;
+    for line in yaml_str.split('\n') {
+        if let Some(indent) = block_indent {
+            if line.trim().is_empty() || leading_whitespace(line) > indent {
+                out.push(line.to_string());
+                continue;
+            }
+            block_indent = None;
+        }
+        let (masked, begins_block_scalar) = mask_line(line);
+        if begins_block_scalar {
+            block_indent = Some(leading_whitespace(line));
+        }
+        out.push(masked);
+    }
+    out.join("\n")
+}
+
+fn leading_whitespace(line: &str) -> usize {
+    line.chars().take_while(|c| *c == ' ' || *c == '\t').count()
+}
+
+// does `code` (a line with any trailing comment already stripped off) end
+// with a `|`/`>` block scalar indicator, optionally followed by a
+// chomping (`+`/`-`) and/or explicit indentation (a digit) indicator, in
+// either order (`|2-` and `|-2` are both valid)?
+fn starts_block_scalar(code: &str) -> bool {
+    let trimmed = code.trim_end();
+    let bytes = trimmed.as_bytes();
+    let mut idx = bytes.len();
+    let mut seen_chomp = false;
+    let mut seen_indent = false;
+    for _ in 0..2 {
+        match bytes.get(idx.wrapping_sub(1)) {
+            Some(b'+') | Some(b'-') if !seen_chomp => {
+                seen_chomp = true;
+                idx -= 1;
+            }
+            Some(b'1'..=b'9') if !seen_indent => {
+                seen_indent = true;
+                idx -= 1;
+            }
+            _ => break,
+        }
+    }
+    idx > 0 && matches!(bytes[idx - 1], b'|' | b'>')
+}
+
+// returns the line with any comment-dwelling `${{ .. }}` masked, and
+// whether the line (outside of any comment) opens a `|`/`>` block
+// scalar, so the caller knows to stop scanning subsequent lines for
+// comments until that block ends.
+fn mask_line(line: &str) -> (String, bool) {
+    let mut quote: Option<char> = None;
+    let mut prev_is_space = true;
+    let mut comment_start = None;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if let Some(q) = quote {
+            if c == '\\' && q == '"' {
+                // an escaped character in a double-quoted scalar (eg the
+                // `\"` in "she said \" hi"): skip over it so it can't be
+                // mistaken for the quote that ends the scalar
+                chars.next();
+                prev_is_space = false;
+                continue;
+            }
+            if c == q {
+                quote = None;
+            }
+            prev_is_space = false;
+            continue;
+        }
+        if c == '\'' || c == '"' {
+            quote = Some(c);
+            prev_is_space = false;
+            continue;
+        }
+        if c == '#' && prev_is_space {
+            comment_start = Some(i);
+            break;
+        }
+        prev_is_space = c == ' ' || c == '\t';
+    }
+    let code = match comment_start {
+        Some(idx) => &line[..idx],
+        None => line,
+    };
+    let begins_block_scalar = starts_block_scalar(code);
+    let masked = match comment_start {
+        Some(idx) => {
+            let (code, comment) = line.split_at(idx);
+            let masked = comment
+                .replace("${{", ESCAPED_OPEN)
+                .replace("}}", ESCAPED_CLOSE);
+            format!("{}{}", code, masked)
+        }
+        None => line.to_string(),
+    };
+    (masked, begins_block_scalar)
+}
+
+// reverse `mask_comment_references`, restoring the original `${{`/`}}`
+// text once every substitution pass has run
+pub(crate) fn unmask_comment_references(yaml_str: &str) -> String {
+    yaml_str
+        .replace(ESCAPED_OPEN, "${{")
+        .replace(ESCAPED_CLOSE, "}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_a_reference_in_a_real_comment() {
+        let out = mask_comment_references("a: 1 # ${{ 0 }}\n");
+        assert!(out.contains(ESCAPED_OPEN));
+    }
+
+    #[test]
+    fn leaves_a_hash_inside_a_block_scalar_alone() {
+        let out = mask_comment_references("description: |\n  # Heading ${{ 0 }}\n");
+        assert!(!out.contains(ESCAPED_OPEN));
+        assert!(out.contains("${{ 0 }}"));
+    }
+
+    #[test]
+    fn block_scalar_ends_once_indentation_drops_back() {
+        let out = mask_comment_references(
+            "description: |\n  # Heading\nafter: 1 # ${{ 0 }}\n",
+        );
+        assert!(out.contains(ESCAPED_OPEN));
+    }
+
+    #[test]
+    fn escaped_quote_does_not_end_a_quoted_scalar_early() {
+        let out = mask_comment_references("msg: \"she said \\\" hi # ${{ 0 }}\"\n");
+        assert!(!out.contains(ESCAPED_OPEN));
+        assert!(out.contains("${{ 0 }}"));
+    }
+
+    #[test]
+    fn recognizes_combined_chomping_and_indentation_headers() {
+        for header in ["|2-", "|-2", ">2+", "|2", "|-", "|"] {
+            assert!(starts_block_scalar(header), "expected {} to be recognized", header);
+        }
+        assert!(!starts_block_scalar("count: 5"));
+    }
+
+    #[test]
+    fn leaves_a_hash_alone_inside_a_two_char_header_block_scalar() {
+        let out = mask_comment_references("description: |2-\n  # Heading ${{ 0 }}\n");
+        assert!(!out.contains(ESCAPED_OPEN));
+        assert!(out.contains("${{ 0 }}"));
+    }
+}
@@ -0,0 +1,267 @@
+use std::fmt;
+
+use context_based_variable_substitution::Context;
+use yaml_rust::Yaml;
+
+// how many near-miss suggestions to surface in a `SubstitutionError`
+const MAX_CANDIDATES: usize = 3;
+// candidates further than this from the missing key aren't useful enough
+// to suggest
+const MAX_CANDIDATE_DISTANCE: usize = 4;
+
+// a `${{ .. }}` reference whose key could not be found in the context it
+// was looked up against, along with enough information to point a user
+// straight at the problem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubstitutionError {
+    pub key: String,
+    pub line: usize,
+    pub column: usize,
+    pub file_path: Option<String>,
+    pub candidates: Vec<String>,
+}
+
+impl fmt::Display for SubstitutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = self.file_path.as_deref().unwrap_or("<string>");
+        write!(f, "{}:{}:{}: unknown variable '{}'", file, self.line, self.column, self.key)?;
+        if let Some(best) = self.candidates.first() {
+            write!(f, " (did you mean '{}'?)", best)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SubstitutionError {}
+
+// the error type for the `try_read_yaml_*` family: either a missing
+// variable (reported as a `SubstitutionError`), or one of the same io
+// errors (file, parse) that the non-`try_` functions already return.
+#[derive(Debug)]
+pub enum TryReadError {
+    Io(std::io::Error),
+    Substitution(SubstitutionError),
+}
+
+impl From<std::io::Error> for TryReadError {
+    fn from(e: std::io::Error) -> Self {
+        TryReadError::Io(e)
+    }
+}
+
+impl From<SubstitutionError> for TryReadError {
+    fn from(e: SubstitutionError) -> Self {
+        TryReadError::Substitution(e)
+    }
+}
+
+impl fmt::Display for TryReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryReadError::Io(e) => write!(f, "{}", e),
+            TryReadError::Substitution(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TryReadError {}
+
+// scan `text` for `${{ key }}` tokens, returning the byte range the
+// whole token spans (including the `${{`/`}}` delimiters) and its
+// trimmed inner key. does not handle nested `${{ }}` tokens, same as the
+// substitution crate this complements.
+fn scan_references(text: &str) -> Vec<(usize, usize, &str)> {
+    let mut refs = Vec::new();
+    let mut search_from = 0;
+    while let Some(rel_start) = text[search_from..].find("${{") {
+        let start = search_from + rel_start;
+        let inner_start = start + 3;
+        match text[inner_start..].find("}}") {
+            Some(rel_end) => {
+                let inner_end = inner_start + rel_end;
+                let end = inner_end + 2;
+                refs.push((start, end, text[inner_start..inner_end].trim()));
+                search_from = end;
+            }
+            None => break,
+        }
+    }
+    refs
+}
+
+// convert a byte offset into 1-based (line, column) by counting newlines
+fn line_col_at(text: &str, byte_offset: usize) -> (usize, usize) {
+    let mut line = 1;
+    let mut column = 1;
+    for (i, c) in text.char_indices() {
+        if i >= byte_offset {
+            break;
+        }
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+    (line, column)
+}
+
+// classic dynamic-programming edit distance
+fn levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut dp = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for (i, row) in dp.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for j in 0..=b.len() {
+        dp[0][j] = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            dp[i][j] = (dp[i - 1][j] + 1)
+                .min(dp[i][j - 1] + 1)
+                .min(dp[i - 1][j - 1] + cost);
+        }
+    }
+    dp[a.len()][b.len()]
+}
+
+// every dotted key (and intermediate path) reachable from `node`, used
+// as the candidate pool for near-miss suggestions
+fn collect_dotted_keys(node: &Yaml) -> Vec<String> {
+    let mut out = Vec::new();
+    collect_dotted_keys_into(node, &String::new(), &mut out);
+    out
+}
+
+fn collect_dotted_keys_into(node: &Yaml, prefix: &str, out: &mut Vec<String>) {
+    match node {
+        Yaml::Hash(h) => {
+            for (k, v) in h.iter() {
+                if let Yaml::String(k) = k {
+                    let path = if prefix.is_empty() {
+                        k.clone()
+                    } else {
+                        format!("{}.{}", prefix, k)
+                    };
+                    collect_dotted_keys_into(v, &path, out);
+                    out.push(path);
+                }
+            }
+        }
+        Yaml::Array(a) => {
+            for (i, v) in a.iter().enumerate() {
+                let path = if prefix.is_empty() {
+                    i.to_string()
+                } else {
+                    format!("{}.{}", prefix, i)
+                };
+                collect_dotted_keys_into(v, &path, out);
+                out.push(path);
+            }
+        }
+        _ => {}
+    }
+}
+
+// the closest few candidates to `key`, nearest first
+fn nearest_keys(key: &str, candidates: &[String]) -> Vec<String> {
+    let mut scored: Vec<(usize, &String)> = candidates
+        .iter()
+        .map(|c| (levenshtein(key, c), c))
+        .filter(|(dist, _)| *dist <= MAX_CANDIDATE_DISTANCE)
+        .collect();
+    scored.sort_by_key(|(dist, _)| *dist);
+    scored
+        .into_iter()
+        .take(MAX_CANDIDATES)
+        .map(|(_, c)| c.clone())
+        .collect()
+}
+
+// make sure every `${{ .. }}` reference in `text` resolves against
+// `context` before we let the real (panicking) substitution pass run.
+// `doc` supplies the pool of known keys for near-miss suggestions.
+//
+// `text` has already been through anchor expansion, comment masking, and
+// the cli/env substitution pass, so its line/column numbering no longer
+// matches what the user actually wrote -- especially once anchor
+// expansion has re-emitted the document through `YamlEmitter`, which
+// reflows line breaks entirely. `original_text` is the pristine,
+// never-rewritten source, so the missing token is located there by its
+// literal text instead, falling back to `text`'s own position for a
+// token that genuinely isn't in the original (eg. one carried over from
+// an anchor block to a second place it's aliased).
+pub(crate) fn check_for_missing_references(
+    text: &str,
+    original_text: &str,
+    context: &dyn Context,
+    doc: &Yaml,
+    file_path: Option<&str>,
+) -> Result<(), SubstitutionError> {
+    for (start, end, key) in scan_references(text) {
+        if context.get_value_from_key(key).is_some() {
+            continue;
+        }
+        let (line, column) = match original_text.find(&text[start..end]) {
+            Some(original_start) => line_col_at(original_text, original_start),
+            None => line_col_at(text, start),
+        };
+        return Err(SubstitutionError {
+            key: key.to_string(),
+            line,
+            column,
+            file_path: file_path.map(str::to_string),
+            candidates: nearest_keys(key, &collect_dotted_keys(doc)),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::YamlContext;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn reports_the_original_sources_line_not_the_re_emitted_ones() {
+        // the re-emitted text (simulating what anchor expansion produces)
+        // reflows the document so the reference sits on a different line
+        // than it does in the original source
+        let original = "a: 1\nb: ${{ missing }}\n";
+        let re_emitted = "a: 1\n\n\nb: ${{ missing }}\n";
+        let doc = YamlLoader::load_from_str(original).unwrap().remove(0);
+        let context = YamlContext { yaml: &doc };
+        let err = check_for_missing_references(re_emitted, original, &context, &doc, None)
+            .unwrap_err();
+        assert_eq!(err.line, 2);
+    }
+
+    #[test]
+    fn falls_back_to_the_rewritten_texts_position_when_absent_from_the_original() {
+        // `a`'s reference here only exists in the re-emitted text (as an
+        // anchor-expansion pass might produce, carrying a placeholder
+        // over to a second, aliased location that was never written in
+        // the original source), so there's nothing to find it by in
+        // `original` and the fallback position is used instead
+        let original = "b: ${{ missing }}\n";
+        let re_emitted = "a: ${{ other }}\nb: ${{ missing }}\n";
+        let doc = YamlLoader::load_from_str(original).unwrap().remove(0);
+        let context = YamlContext { yaml: &doc };
+        let err = check_for_missing_references(re_emitted, original, &context, &doc, None)
+            .unwrap_err();
+        assert_eq!(err.key, "other");
+        assert_eq!(err.line, 1);
+    }
+
+    #[test]
+    fn scan_references_reports_the_whole_token_span() {
+        let refs = scan_references("a: ${{ x }} b: ${{ y }}");
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs[0], (3, 11, "x"));
+    }
+}
@@ -1,11 +1,42 @@
 use context_based_variable_substitution::*;
 use yaml_rust::Yaml;
-use yaml_rust::YamlLoader;
+use yaml_rust::{YamlEmitter, YamlLoader};
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{ErrorKind, Error};
 
+mod anchors;
+mod comments;
+mod errors;
+mod output_format;
+mod transclusion;
+mod typed;
+pub use anchors::{expand_yaml_anchors, expand_yaml_anchors_str, SHARED_ANCHORS_KEY};
+use comments::{mask_comment_references, unmask_comment_references};
+pub use errors::{SubstitutionError, TryReadError};
+use errors::check_for_missing_references;
+pub use output_format::{read_document_as, yaml_to_json_value, OutputFormat};
+pub use transclusion::transclude_structural_references;
+pub use typed::{read_typed_from_file, read_typed_from_string};
+
+// re-serialize `docs` back into yaml text. used by the anchor expansion
+// and structural transclusion passes, both of which rewrite the parsed
+// tree and need to hand text back to the string-substitution pipeline.
+pub(crate) fn emit_yaml_docs_to_string(docs: &[Yaml]) -> Result<String, Error> {
+    let mut out = String::new();
+    {
+        let mut emitter = YamlEmitter::new(&mut out);
+        for doc in docs {
+            emitter.dump(doc).map_err(|e| {
+                let err_msg = format!("failed to re-emit yaml: {}", e);
+                Error::new(ErrorKind::InvalidData, err_msg)
+            })?;
+        }
+    }
+    Ok(out)
+}
+
 pub fn get_env_str(key: &str) -> Option<String> {
     match env::var(key) {
         Ok(s) => Some(s),
@@ -36,42 +67,43 @@ pub fn get_string_from_yaml_object(yaml_obj: &Yaml) -> Option<String> {
         Yaml::Boolean(b) => b.to_string(),
         Yaml::Null => "null".into(),
 
+        // objects and arrays have no sensible string representation here.
+        // a whole-value reference to one (eg. `field: ${{ other.thing }}`
+        // where `other.thing` is a hash/array) is instead spliced in as
+        // structure by `transclusion::transclude_structural_references`,
+        // which runs before this function ever sees that reference.
         _ => return None,
-        // TODO: is it possible to transclude in place
-        // segments of yaml? say the user had something like:
-        // custom:
-        //    field: ${{ other.thing }}
-        // other:
-        //    thing:
-        //       hello: world
-        //
-        // could that then return to custom.field.hello = world?
-        // Yaml::Array(_) => "array",
-        // Yaml::Hash(_) => "object",
-        // Yaml::Alias(_) => "alias",
-        // Yaml::BadValue => "BAD_YAML_VALUE",
     };
     s.into()
 }
 
 
+// walk `yaml` following a dotted key such as `custom.somevar.arg1`,
+// indexing into arrays by parsed usize and everything else by map key.
+// shared by `YamlContext` (which coerces the result to a string) and the
+// structural transclusion pass (which needs the raw node).
+pub(crate) fn yaml_at_path<'a>(yaml: &'a Yaml, key: &str) -> &'a Yaml {
+    let key_split = key.split(".");
+    let mut yobj = yaml;
+    for k in key_split {
+        if yobj.is_array() {
+            // then we index as if k is a usize:
+            if let Ok(k_usize) = k.parse::<usize>() {
+                yobj = &yobj[k_usize];
+                continue;
+            }
+        }
+        yobj = &yobj[k];
+    }
+    yobj
+}
+
 pub struct YamlContext<'a> {
     pub yaml: &'a Yaml,
 }
 impl<'a> Context for YamlContext<'a> {
     fn get_value_from_key(&self, key: &str) -> Option<String> {
-        let key_split = key.split(".");
-        let mut yobj = self.yaml;
-        for k in key_split {
-            if yobj.is_array() {
-                // then we index as if k is a usize:
-                if let Ok(k_usize) = k.parse::<usize>() {
-                    yobj = &yobj[k_usize];
-                    continue;
-                }
-            }
-            yobj = &yobj[k];
-        }
+        let yobj = yaml_at_path(self.yaml, key);
         if yobj.is_badvalue() {
             None
         } else {
@@ -118,14 +150,24 @@ pub fn load_yaml_from_str(
     Ok(yaml_doc)
 }
 
-// given a yaml text as a string, perform substitutions
-// first via the cli and environemnt variables context
-// and then again with the context of the yaml object
-// this second pass allows yaml fields to reference each other
-pub fn read_yaml_string_from_string(
+// runs everything that happens before the final, possibly-failing
+// cross-reference substitution pass: anchor expansion, the cli/env pass,
+// and structural transclusion. shared by `read_yaml_string_from_string`
+// and `try_read_yaml_string_from_string` so they stay in lock step.
+fn prepare_for_cross_reference_pass(
     yaml_str: &str,
     cli_args: Vec<String>,
-) -> Result<String, Error> {
+) -> Result<(String, Vec<Yaml>), Error> {
+    // resolve any `&anchor`/`*alias` pairs (and strip the reserved
+    // anchors-only key) before we even touch `${{ .. }}` substitution, so
+    // that a placeholder written inside an anchored block is carried over
+    // to every place that anchor is aliased
+    let yaml_str = expand_yaml_anchors_str(yaml_str)?;
+    // a `${{ .. }}` that only appears inside a yaml comment is never
+    // meant to be substituted, so hide it from both passes below; it's
+    // restored once the whole pipeline is done
+    let yaml_str = mask_comment_references(&yaml_str);
+
     let arg_and_env_context = ArgEnvContext {
         cli_args: &cli_args,
     };
@@ -137,7 +179,29 @@ pub fn read_yaml_string_from_string(
         &arg_and_env_context,
         FailureMode::FM_ignore,
     );
-    let yaml_doc = load_yaml_from_str(&yaml_out_str)?;
+    let mut yaml_doc = load_yaml_from_str(&yaml_out_str)?;
+    // a field whose entire value is a single `${{ key }}` reference to an
+    // object or array is spliced in as structure rather than left for the
+    // string-level pass below, which can only ever produce a string. this
+    // has to happen before that pass, since otherwise it would try (and
+    // fail) to coerce the referenced object/array into a string itself
+    let transcluded = transclude_structural_references(&yaml_doc[0], &yaml_doc[0]);
+    if transcluded != yaml_doc[0] {
+        yaml_out_str = emit_yaml_docs_to_string(&[transcluded])?;
+        yaml_doc = load_yaml_from_str(&yaml_out_str)?;
+    }
+    Ok((yaml_out_str, yaml_doc))
+}
+
+// given a yaml text as a string, perform substitutions
+// first via the cli and environemnt variables context
+// and then again with the context of the yaml object
+// this second pass allows yaml fields to reference each other
+pub fn read_yaml_string_from_string(
+    yaml_str: &str,
+    cli_args: Vec<String>,
+) -> Result<String, Error> {
+    let (mut yaml_out_str, yaml_doc) = prepare_for_cross_reference_pass(yaml_str, cli_args)?;
     // and after that, we create a temporary, dummy, yaml context
     // to be used to fill in the rest of the variable references
     // using the filled in context from the envs and args above
@@ -155,7 +219,36 @@ pub fn read_yaml_string_from_string(
         &yaml_context,
         FailureMode::FM_panic,
     );
-    Ok(yaml_out_str)
+    Ok(unmask_comment_references(&yaml_out_str))
+}
+
+// same as `read_yaml_string_from_string`, but instead of panicking when
+// the cross-reference pass hits an unresolvable `${{ .. }}`, it reports a
+// `SubstitutionError` describing exactly which key, and where
+pub fn try_read_yaml_string_from_string(
+    yaml_str: &str,
+    cli_args: Vec<String>,
+) -> Result<String, TryReadError> {
+    try_read_yaml_string_from_string_with_path(yaml_str, cli_args, None)
+}
+
+fn try_read_yaml_string_from_string_with_path(
+    yaml_str: &str,
+    cli_args: Vec<String>,
+    file_path: Option<&str>,
+) -> Result<String, TryReadError> {
+    let (yaml_out_str, yaml_doc) = prepare_for_cross_reference_pass(yaml_str, cli_args)?;
+    let yaml_context = YamlContext {
+        yaml: &yaml_doc[0],
+    };
+    // `yaml_str` is the pristine, never-rewritten source; `yaml_out_str`
+    // has been through anchor expansion (which can reflow line breaks
+    // entirely) and substitution, so its own positions are reported
+    // against `yaml_str` instead of themselves
+    check_for_missing_references(&yaml_out_str, yaml_str, &yaml_context, &yaml_doc[0], file_path)?;
+    // every reference resolves, so this can no longer panic
+    let yaml_out_str = replace_all_from(&yaml_out_str, &yaml_context, FailureMode::FM_panic);
+    Ok(unmask_comment_references(&yaml_out_str))
 }
 
 // given a path to a file (and cli args for context)
@@ -186,6 +279,19 @@ pub fn read_yaml_string_from_file(
     read_yaml_string_from_string(yaml_str.as_str(), cli_args)
 }
 
+// same as `read_yaml_string_from_file`, but reports a `SubstitutionError`
+// (carrying `file_path`) instead of panicking on an unresolvable
+// `${{ .. }}` reference
+pub fn try_read_yaml_string_from_file(
+    file_path: &str,
+    cli_args: Vec<String>,
+) -> Result<String, TryReadError> {
+    let mut file = File::open(file_path)?;
+    let mut yaml_str = String::new();
+    file.read_to_string(&mut yaml_str)?;
+    try_read_yaml_string_from_string_with_path(yaml_str.as_str(), cli_args, Some(file_path))
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -237,10 +343,16 @@ mod tests {
     }
 
     #[test]
-    #[ignore = "not implemented yet... need to not try to substitute commented variables"]
     fn variables_in_comments_dont_cause_errors() {
         let cli_arg_context = vec!["some_arg".into()];
         let my_yaml_docs = read_yaml_from_file(TEST_COMMENTS_FILE, cli_arg_context).unwrap();
         let my_yaml_doc = &my_yaml_docs[0];
+        // the `${{ .. }}` reference living in the comment above `real_value`
+        // is never meant to be substituted, so it shouldn't have caused a
+        // panic/parse failure, and the real value should substitute as normal
+        assert_eq!(
+            my_yaml_doc["real_value"].as_str().unwrap(),
+            "some_arg"
+        );
     }
 }
@@ -0,0 +1,116 @@
+use serde_json::{Map, Number, Value};
+use std::io::{Error, ErrorKind};
+use yaml_rust::Yaml;
+
+use crate::{get_string_from_yaml_object, read_yaml_from_file, read_yaml_string_from_file};
+
+// which format `read_document_as` should serialize the substituted
+// document into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Yaml,
+    Json,
+    Toml,
+}
+
+// convert a resolved `Yaml` node into a `serde_json::Value`, the neutral
+// value model both the json and toml emitters are driven from.
+pub fn yaml_to_json_value(yaml: &Yaml) -> Value {
+    match yaml {
+        Yaml::Real(r) => r
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+        Yaml::Integer(i) => Value::Number((*i).into()),
+        Yaml::String(s) => Value::String(s.clone()),
+        Yaml::Boolean(b) => Value::Bool(*b),
+        Yaml::Array(a) => Value::Array(a.iter().map(yaml_to_json_value).collect()),
+        Yaml::Hash(h) => {
+            let mut map = Map::new();
+            for (k, v) in h.iter() {
+                // object keys must be strings; coerce non-string keys the
+                // same way the rest of this crate coerces scalars
+                let key = match k {
+                    Yaml::String(s) => s.clone(),
+                    other => get_string_from_yaml_object(other).unwrap_or_default(),
+                };
+                map.insert(key, yaml_to_json_value(v));
+            }
+            Value::Object(map)
+        }
+        Yaml::Null | Yaml::Alias(_) | Yaml::BadValue => Value::Null,
+    }
+}
+
+// run the full substitution pipeline on the yaml at `file_path` and
+// serialize the resolved document as `format`
+pub fn read_document_as(
+    file_path: &str,
+    cli_args: Vec<String>,
+    format: OutputFormat,
+) -> Result<String, Error> {
+    match format {
+        OutputFormat::Yaml => read_yaml_string_from_file(file_path, cli_args),
+        OutputFormat::Json => {
+            let value = yaml_to_json_value(&read_yaml_from_file(file_path, cli_args)?[0]);
+            serde_json::to_string_pretty(&value).map_err(|e| {
+                let err_msg = format!("Failed to serialize substituted yaml to json:\n{}", e);
+                Error::new(ErrorKind::InvalidData, err_msg)
+            })
+        }
+        OutputFormat::Toml => {
+            let value = yaml_to_json_value(&read_yaml_from_file(file_path, cli_args)?[0]);
+            let toml_value: toml::Value = serde_json::from_value(value).map_err(|e| {
+                let err_msg = format!("Failed to convert substituted yaml to toml:\n{}", e);
+                Error::new(ErrorKind::InvalidData, err_msg)
+            })?;
+            toml::to_string_pretty(&toml_value).map_err(|e| {
+                let err_msg = format!("Failed to serialize substituted yaml to toml:\n{}", e);
+                Error::new(ErrorKind::InvalidData, err_msg)
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn load(s: &str) -> Yaml {
+        YamlLoader::load_from_str(s).unwrap().remove(0)
+    }
+
+    #[test]
+    fn converts_scalars() {
+        let doc = load("a: 1\nb: 1.5\nc: hello\nd: true\ne: ~\n");
+        assert_eq!(yaml_to_json_value(&doc["a"]), Value::Number(1.into()));
+        assert_eq!(
+            yaml_to_json_value(&doc["b"]),
+            Value::Number(Number::from_f64(1.5).unwrap())
+        );
+        assert_eq!(yaml_to_json_value(&doc["c"]), Value::String("hello".into()));
+        assert_eq!(yaml_to_json_value(&doc["d"]), Value::Bool(true));
+        assert_eq!(yaml_to_json_value(&doc["e"]), Value::Null);
+    }
+
+    #[test]
+    fn converts_nested_arrays_and_objects() {
+        let doc = load("a:\n  - 1\n  - 2\nb:\n  c: 3\n");
+        let value = yaml_to_json_value(&doc);
+        assert_eq!(
+            value["a"],
+            Value::Array(vec![Value::Number(1.into()), Value::Number(2.into())])
+        );
+        assert_eq!(value["b"]["c"], Value::Number(3.into()));
+    }
+
+    #[test]
+    fn coerces_non_string_hash_keys() {
+        let doc = load("1: one\n");
+        let value = yaml_to_json_value(&doc);
+        assert_eq!(value["1"], Value::String("one".into()));
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashSet;
+
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+use crate::yaml_at_path;
+
+// returns the inner key of `s`, if and only if `s` is, once trimmed, a
+// single whole `${{ key }}` reference and nothing else. a reference
+// embedded inside a larger string (eg. `"hello ${{ x }}"`) is left alone
+// here so the existing string-level substitution still handles it.
+fn whole_reference_key(s: &str) -> Option<&str> {
+    let trimmed = s.trim();
+    let inner = trimmed.strip_prefix("${{")?.strip_suffix("}}")?;
+    let inner = inner.trim();
+    if inner.is_empty() || inner.contains("${{") {
+        None
+    } else {
+        Some(inner)
+    }
+}
+
+// walk `node`, splicing in a deep clone of the subtree at `root` wherever
+// a scalar string is, in its entirety, a single `${{ key }}` reference
+// that resolves to an object or array. this lets one field reference
+// another part of the document and inherit its whole structure, eg:
+//   custom:
+//     field: ${{ other.thing }}
+//   other:
+//     thing:
+//       hello: world
+// after transclusion, `custom.field.hello` is `world`. only whole-value
+// references are transcluded this way; a reference that's part of a
+// larger string still goes through the usual string substitution.
+pub fn transclude_structural_references(node: &Yaml, root: &Yaml) -> Yaml {
+    let mut visiting = HashSet::new();
+    transclude(node, root, &mut visiting)
+}
+
+// `visiting` holds the keys currently being resolved along the current
+// reference chain. a key reappearing in it means `field: ${{ key }}`
+// eventually refers back to itself (directly or through other whole-value
+// references), so it's left as the literal `${{ key }}` string instead of
+// being followed again, which would otherwise recurse forever.
+fn transclude(node: &Yaml, root: &Yaml, visiting: &mut HashSet<String>) -> Yaml {
+    match node {
+        Yaml::String(s) => {
+            if let Some(key) = whole_reference_key(s) {
+                let resolved = yaml_at_path(root, key);
+                let is_structural = resolved.as_hash().is_some() || resolved.as_vec().is_some();
+                if is_structural && !visiting.contains(key) {
+                    visiting.insert(key.to_string());
+                    let out = transclude(resolved, root, visiting);
+                    visiting.remove(key);
+                    return out;
+                }
+            }
+            node.clone()
+        }
+        Yaml::Hash(h) => {
+            let mut new_hash = Hash::new();
+            for (k, v) in h.iter() {
+                new_hash.insert(transclude(k, root, visiting), transclude(v, root, visiting));
+            }
+            Yaml::Hash(new_hash)
+        }
+        Yaml::Array(a) => {
+            Yaml::Array(a.iter().map(|v| transclude(v, root, visiting)).collect())
+        }
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn load(s: &str) -> Yaml {
+        YamlLoader::load_from_str(s).unwrap().remove(0)
+    }
+
+    #[test]
+    fn splices_whole_value_references() {
+        let doc = load(
+            "custom:\n  field: ${{ other.thing }}\nother:\n  thing:\n    hello: world\n",
+        );
+        let out = transclude_structural_references(&doc, &doc);
+        assert_eq!(
+            out["custom"]["field"]["hello"].as_str().unwrap(),
+            "world"
+        );
+    }
+
+    #[test]
+    fn leaves_embedded_references_alone() {
+        let doc = load("a: \"hello ${{ b }}\"\nb: world\n");
+        let out = transclude_structural_references(&doc, &doc);
+        assert_eq!(out["a"].as_str().unwrap(), "hello ${{ b }}");
+    }
+
+    #[test]
+    fn self_reference_does_not_recurse_forever() {
+        let doc = load("custom:\n  field: ${{ custom }}\n");
+        // must return instead of overflowing the stack; `custom` is spliced
+        // in once, and the copy's own `${{ custom }}` is left as-is
+        let out = transclude_structural_references(&doc, &doc);
+        assert_eq!(out["custom"]["field"]["field"].as_str().unwrap(), "${{ custom }}");
+    }
+
+    #[test]
+    fn mutual_cycle_does_not_recurse_forever() {
+        let doc = load("a:\n  via_b: ${{ b }}\nb:\n  via_a: ${{ a }}\n");
+        // must terminate instead of recursing between `a` and `b` forever;
+        // the chain breaks on the first key it has already visited
+        let out = transclude_structural_references(&doc, &doc);
+        assert_eq!(
+            out["a"]["via_b"]["via_a"]["via_b"].as_str().unwrap(),
+            "${{ b }}"
+        );
+    }
+}
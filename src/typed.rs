@@ -0,0 +1,32 @@
+use serde::de::DeserializeOwned;
+use std::io::{Error, ErrorKind};
+
+use crate::{read_yaml_string_from_file, read_yaml_string_from_string};
+
+// run the full two-pass substitution over `yaml_str` and deserialize the
+// result straight into `T`, instead of making the caller navigate the
+// substituted `Yaml::Hash`/`Yaml::Array` by hand.
+pub fn read_typed_from_string<T: DeserializeOwned>(
+    yaml_str: &str,
+    cli_args: Vec<String>,
+) -> Result<T, Error> {
+    let substituted = read_yaml_string_from_string(yaml_str, cli_args)?;
+    deserialize_substituted(&substituted)
+}
+
+// same as `read_typed_from_string`, but reads the yaml from `file_path`
+// first
+pub fn read_typed_from_file<T: DeserializeOwned>(
+    file_path: &str,
+    cli_args: Vec<String>,
+) -> Result<T, Error> {
+    let substituted = read_yaml_string_from_file(file_path, cli_args)?;
+    deserialize_substituted(&substituted)
+}
+
+fn deserialize_substituted<T: DeserializeOwned>(substituted: &str) -> Result<T, Error> {
+    serde_yaml::from_str(substituted).map_err(|e| {
+        let err_msg = format!("Failed to deserialize substituted yaml:\n{}", e);
+        Error::new(ErrorKind::InvalidData, err_msg)
+    })
+}